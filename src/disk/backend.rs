@@ -0,0 +1,374 @@
+//! A pure-Rust partition-table backend, used as an alternative to libparted.
+//!
+//! The [`PartitionTableBackend`] trait abstracts the three operations the rest
+//! of the crate needs from a partition table — reporting its type, validating a
+//! proposed layout, and committing partitions — behind a common interface.
+//! Two implementations are provided: [`GptBackend`], built on a gptman-style GPT
+//! reader/writer, and [`MbrBackend`], built on a mbrman-style MBR reader/writer.
+//! Because both operate over any `Read + Write + Seek`, disk-image files are
+//! first-class targets alongside real block devices, and the common case no
+//! longer requires the libparted C dependency at runtime.
+
+use super::{
+    DiskError, FileSystemType, PartitionBuilder, PartitionFlag, PartitionTable, PartitionType,
+};
+use std::io::{Read, Seek, Write};
+
+/// A target the backend can write a table to: a real block device or a plain
+/// disk-image file. Blanket-implemented for anything that is `Read + Write +
+/// Seek` so [`PartitionTableBackend`] can stay object-safe and still be boxed
+/// by [`backend_for`].
+pub trait DiskDevice: Read + Write + Seek {}
+impl<D: Read + Write + Seek> DiskDevice for D {}
+
+// GPT partition type GUIDs, in the mixed-endian byte order in which they are
+// stored in the entry array.
+const GUID_EFI:   [u8; 16] = [0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11,
+                              0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B];
+const GUID_SWAP:  [u8; 16] = [0x6D, 0xFD, 0x57, 0x06, 0xAB, 0xA4, 0xC4, 0x43,
+                              0x84, 0xE5, 0x09, 0x33, 0xC8, 0x4B, 0x4F, 0x4F];
+const GUID_LINUX: [u8; 16] = [0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47,
+                              0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4];
+
+/// The GPT type GUID implied by a builder: an ESP flag wins, otherwise the
+/// filesystem selects between the Linux swap and generic Linux filesystem GUIDs.
+fn gpt_type_guid(builder: &PartitionBuilder) -> [u8; 16] {
+    if builder.flags.contains(&PartitionFlag::PED_PARTITION_ESP) {
+        GUID_EFI
+    } else if builder.filesystem == Some(FileSystemType::Swap) {
+        GUID_SWAP
+    } else {
+        GUID_LINUX
+    }
+}
+
+/// A unique partition GUID for the entry. Derived from the partition's extent so
+/// it is stable across a re-run without pulling in a random-number dependency.
+fn gpt_unique_guid(builder: &PartitionBuilder) -> [u8; 16] {
+    let mut guid = [0u8; 16];
+    guid[..8].copy_from_slice(&builder.start_sector.to_le_bytes());
+    guid[8..].copy_from_slice(&builder.end_sector.to_le_bytes());
+    guid
+}
+
+/// The GPT attribute bits implied by a builder. Only the legacy-BIOS-bootable
+/// bit (bit 2) is set today, when the boot flag is present.
+fn gpt_attribute_bits(builder: &PartitionBuilder) -> u64 {
+    if builder.flags.contains(&PartitionFlag::PED_PARTITION_BOOT) {
+        1 << 2
+    } else {
+        0
+    }
+}
+
+/// The UTF-16 partition name for the entry, defaulting to empty when the builder
+/// carries no label.
+fn partition_name(builder: &PartitionBuilder) -> String {
+    builder.name.clone().unwrap_or_default()
+}
+
+/// The MBR boot indicator implied by a builder.
+fn mbr_boot_flag(builder: &PartitionBuilder) -> u8 {
+    if builder.flags.contains(&PartitionFlag::PED_PARTITION_BOOT) {
+        mbrman::BOOT_ACTIVE
+    } else {
+        mbrman::BOOT_INACTIVE
+    }
+}
+
+// A standard large-disk CHS geometry (255 heads, 63 sectors/track) used for the
+// LBA-assisted translation modern BIOSes expect. The cylinder field is only 10
+// bits wide, so addresses beyond the ceiling are pinned to the all-but-maximum
+// marker and addressed purely by LBA.
+const MBR_HEADS:   u32 = 255;
+const MBR_SECTORS: u32 = 63;
+
+/// Translates a linear LBA into a CHS triple under the standard 255/63 geometry.
+fn lba_to_chs(lba: u32) -> mbrman::CHS {
+    let sector = (lba % MBR_SECTORS) + 1;
+    let track = lba / MBR_SECTORS;
+    let head = track % MBR_HEADS;
+    let cylinder = track / MBR_HEADS;
+
+    if cylinder > 1023 {
+        mbrman::CHS { cylinder: 1023, head: (MBR_HEADS - 1) as u8, sector: MBR_SECTORS as u8 }
+    } else {
+        mbrman::CHS { cylinder: cylinder as u16, head: head as u8, sector: sector as u8 }
+    }
+}
+
+/// The MBR system-id byte implied by a builder's filesystem.
+fn mbr_system_id(builder: &PartitionBuilder) -> u8 {
+    if builder.flags.contains(&PartitionFlag::PED_PARTITION_ESP) {
+        0xEF
+    } else {
+        match builder.filesystem {
+            Some(FileSystemType::Swap) => 0x82,
+            Some(FileSystemType::Fat32) => 0x0C,
+            _ => 0x83,
+        }
+    }
+}
+
+/// Operations a partition-table implementation must provide so that
+/// `Disks::commit` can select a backend without caring how the table is stored.
+pub trait PartitionTableBackend {
+    /// The kind of table this backend manages.
+    fn get_table_type(&self) -> Option<PartitionTable>;
+
+    /// Validates that the pending partitions are legal for the table (e.g. the
+    /// four-primary / extended restrictions of MBR).
+    fn validate_partition_table(&self, part_type: PartitionType) -> Result<(), DiskError>;
+
+    /// Stages a new partition derived from `builder` into the in-memory table.
+    fn add_partition(&mut self, builder: &PartitionBuilder) -> Result<(), DiskError>;
+
+    /// Writes the table out to the target, which may be a block device or a
+    /// plain disk-image file.
+    fn commit(&mut self, device: &mut dyn DiskDevice) -> Result<(), DiskError>;
+}
+
+/// A GPT backend.
+///
+/// Manages the protective MBR at LBA0, the primary and backup headers (each with
+/// a CRC32 over the header and the partition entry array), and the array of
+/// 128-byte entries — type GUID, unique GUID, starting/ending LBA, attribute
+/// flags, and the UTF-16LE partition name.
+pub struct GptBackend {
+    sector_size: u64,
+    inner:       gptman::GPT,
+}
+
+impl GptBackend {
+    /// Reads an existing GPT from the target.
+    pub fn read<D: Read + Seek>(device: &mut D, sector_size: u64) -> Result<GptBackend, DiskError> {
+        let inner = gptman::GPT::read_from(device, sector_size)
+            .map_err(|why| DiskError::DiskGet { why })?;
+        Ok(GptBackend { sector_size, inner })
+    }
+
+    /// Creates an empty GPT (protective MBR + fresh primary/backup headers) for a
+    /// device of `total_sectors` sectors.
+    pub fn create(sector_size: u64, total_sectors: u64) -> Result<GptBackend, DiskError> {
+        let inner = gptman::GPT::new_from(sector_size, total_sectors)
+            .map_err(|why| DiskError::DiskGet { why })?;
+        Ok(GptBackend { sector_size, inner })
+    }
+}
+
+impl PartitionTableBackend for GptBackend {
+    fn get_table_type(&self) -> Option<PartitionTable> {
+        Some(PartitionTable::Gpt)
+    }
+
+    fn validate_partition_table(&self, _part_type: PartitionType) -> Result<(), DiskError> {
+        // GPT imposes no primary/extended distinction; every partition is a
+        // full entry in the array.
+        Ok(())
+    }
+
+    fn add_partition(&mut self, builder: &PartitionBuilder) -> Result<(), DiskError> {
+        // gptman indexes the entry array by partition number (1..=len), unlike
+        // mbrman's find_optimal_place which returns a slot. find_first_place
+        // returns a starting LBA, not a slot, so pick the first free partition
+        // number ourselves and keep the builder's start/end LBAs.
+        let index = self
+            .inner
+            .iter()
+            .find(|(_, part)| part.is_unused())
+            .map(|(index, _)| index)
+            .ok_or(DiskError::PartitionOOB)?;
+
+        self.inner[index] = gptman::GPTPartitionEntry {
+            partition_type_guid:     gpt_type_guid(builder),
+            unique_partition_guid:   gpt_unique_guid(builder),
+            starting_lba:            builder.start_sector,
+            ending_lba:              builder.end_sector,
+            attribute_bits:          gpt_attribute_bits(builder),
+            partition_name:          partition_name(builder).into(),
+        };
+
+        Ok(())
+    }
+
+    fn commit(&mut self, device: &mut dyn DiskDevice) -> Result<(), DiskError> {
+        // Writing the GPT also refreshes the protective MBR at LBA0 and the
+        // backup header/array at the end of the device.
+        self.inner
+            .write_into(device)
+            .map_err(|why| DiskError::DiskSync { why })?;
+        Ok(())
+    }
+}
+
+/// An MBR backend.
+///
+/// Handles the four primary slots plus chained EBR logical volumes, computing
+/// CHS addresses automatically under the standard 255-head / 63-sector geometry.
+pub struct MbrBackend {
+    sector_size: u64,
+    inner:       mbrman::MBR,
+}
+
+impl MbrBackend {
+    /// Reads an existing MBR from the target.
+    pub fn read<D: Read + Seek>(device: &mut D, sector_size: u64) -> Result<MbrBackend, DiskError> {
+        let inner = mbrman::MBR::read_from(device, sector_size as u32)
+            .map_err(|why| DiskError::DiskGet { why })?;
+        Ok(MbrBackend { sector_size, inner })
+    }
+
+    /// Creates an empty MBR for a device of `total_sectors` sectors.
+    pub fn create(sector_size: u64, total_sectors: u64) -> Result<MbrBackend, DiskError> {
+        let inner = mbrman::MBR::new_from(device_geometry(), total_sectors as u32)
+            .map_err(|why| DiskError::DiskGet { why })?;
+        Ok(MbrBackend { sector_size, inner })
+    }
+}
+
+impl PartitionTableBackend for MbrBackend {
+    fn get_table_type(&self) -> Option<PartitionTable> {
+        Some(PartitionTable::Msdos)
+    }
+
+    fn validate_partition_table(&self, part_type: PartitionType) -> Result<(), DiskError> {
+        // MBR allows at most four primary partitions. Only that limit is
+        // checked; a logical partition is not checked for an extended container.
+        let primaries = self
+            .inner
+            .iter()
+            .filter(|(_, part)| part.is_used())
+            .count();
+
+        if part_type == PartitionType::Primary && primaries >= 4 {
+            return Err(DiskError::PrimaryPartitionsExceeded);
+        }
+
+        Ok(())
+    }
+
+    fn add_partition(&mut self, builder: &PartitionBuilder) -> Result<(), DiskError> {
+        // The 32-bit starting-LBA / length fields cannot describe a partition
+        // that ends beyond sector 2^32 - 1 (2 TiB with 512-byte sectors).
+        if builder.end_sector > u64::from(u32::max_value()) {
+            return Err(DiskError::PartitionOOB);
+        }
+
+        let entry = mbrman::MBRPartitionEntry {
+            boot:      mbr_boot_flag(builder),
+            first_chs: lba_to_chs(builder.start_sector as u32),
+            sys:       mbr_system_id(builder),
+            last_chs:  lba_to_chs(builder.end_sector as u32),
+            starting_lba: builder.start_sector as u32,
+            sectors:      builder.sectors() as u32,
+        };
+
+        match builder.part_type {
+            // A logical volume lives inside the extended container, chained from
+            // its own EBR one sector ahead of the volume; mbrman tracks the
+            // chain as its list of logical partitions.
+            PartitionType::Logical => {
+                self.inner.logical_partitions.push(mbrman::LogicalPartition {
+                    partition:        entry,
+                    absolute_ebr_lba: (builder.start_sector as u32).saturating_sub(1),
+                    bootstrap_code:   [0; 446],
+                });
+            }
+            _ => {
+                let index = self
+                    .inner
+                    .find_optimal_place(builder.sectors() as u32)
+                    .ok_or(DiskError::PartitionOOB)?;
+                self.inner[index] = entry;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn commit(&mut self, device: &mut dyn DiskDevice) -> Result<(), DiskError> {
+        self.inner
+            .write_into(device)
+            .map_err(|why| DiskError::DiskSync { why })?;
+        Ok(())
+    }
+}
+
+/// The disk geometry passed to mbrman when creating a fresh table: the standard
+/// 255-head / 63-sector-per-track layout against which [`lba_to_chs`] computes
+/// the per-entry CHS addresses.
+fn device_geometry() -> mbrman::CHS {
+    mbrman::CHS { cylinder: 1023, head: (MBR_HEADS - 1) as u8, sector: MBR_SECTORS as u8 }
+}
+
+/// Creates a fresh, empty backend for a partition table — the `mklabel` path,
+/// used when the whole disk is being repartitioned from scratch.
+pub fn backend_for(
+    table: PartitionTable,
+    sector_size: u64,
+    total_sectors: u64,
+) -> Result<Box<dyn PartitionTableBackend>, DiskError> {
+    match table {
+        PartitionTable::Gpt => Ok(Box::new(GptBackend::create(sector_size, total_sectors)?)),
+        PartitionTable::Msdos => Ok(Box::new(MbrBackend::create(sector_size, total_sectors)?)),
+    }
+}
+
+/// Opens the backend for the table already written to `device`, so the
+/// partitions already on disk are carried over when new ones are layered in.
+pub fn read_backend<D: Read + Seek>(
+    device: &mut D,
+    table: PartitionTable,
+    sector_size: u64,
+) -> Result<Box<dyn PartitionTableBackend>, DiskError> {
+    match table {
+        PartitionTable::Gpt => Ok(Box::new(GptBackend::read(device, sector_size)?)),
+        PartitionTable::Msdos => Ok(Box::new(MbrBackend::read(device, sector_size)?)),
+    }
+}
+
+/// Stages `builders` onto the table already present on `device` and writes it
+/// back, preserving the partitions that are already there.
+///
+/// This is the entry point a caller such as `Disks::commit` drives once it has
+/// resolved the table type and the new partitions to lay down: it reads the
+/// existing table with [`read_backend`], validates and stages every builder into
+/// the free slots, then flushes the table (and, for GPT, the protective MBR and
+/// backup header).
+pub fn commit<D: Read + Write + Seek>(
+    device: &mut D,
+    table: PartitionTable,
+    sector_size: u64,
+    builders: &[PartitionBuilder],
+) -> Result<(), DiskError> {
+    let mut backend = read_backend(device, table, sector_size)?;
+    apply(&mut *backend, builders)?;
+    backend.commit(device)
+}
+
+/// Writes a brand-new table to `device` containing exactly `builders`, replacing
+/// whatever table and partitions were there before (the `mklabel` path). Use
+/// [`commit`] instead to keep the partitions already on disk.
+pub fn commit_new_table<D: Read + Write + Seek>(
+    device: &mut D,
+    table: PartitionTable,
+    sector_size: u64,
+    total_sectors: u64,
+    builders: &[PartitionBuilder],
+) -> Result<(), DiskError> {
+    let mut backend = backend_for(table, sector_size, total_sectors)?;
+    apply(&mut *backend, builders)?;
+    backend.commit(device)
+}
+
+/// Validates and stages every builder into a backend.
+fn apply(
+    backend: &mut dyn PartitionTableBackend,
+    builders: &[PartitionBuilder],
+) -> Result<(), DiskError> {
+    for builder in builders {
+        backend.validate_partition_table(builder.part_type)?;
+        backend.add_partition(builder)?;
+    }
+    Ok(())
+}
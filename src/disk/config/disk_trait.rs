@@ -2,9 +2,304 @@ use super::super::{
     DiskError, Disks, PartitionBuilder, PartitionInfo, PartitionTable, PartitionType, Sector,
 };
 use super::partitions::{check_partition_size, REMOVE};
-use std::fs::File;
-use std::io::Read;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read};
 use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The result of the `SMART` overall-health self-assessment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmartStatus {
+    Passed,
+    Failed,
+}
+
+/// A summary of a drive's `SMART` health, as reported by `smartctl`.
+#[derive(Debug, Clone)]
+pub struct SmartHealth {
+    /// The overall-health self-assessment result (`smartctl -H`).
+    pub status: SmartStatus,
+    /// The raw `Reallocated_Sector_Ct` attribute, when present.
+    pub reallocated_sectors: Option<u64>,
+    /// The raw wear indicator (`Wear_Leveling_Count` / `Media_Wearout_Indicator`),
+    /// when present; meaningful for SSDs.
+    pub wear_leveling: Option<u64>,
+}
+
+/// Parses the combined output of `smartctl -H -A` into a [`SmartHealth`].
+fn parse_smart(output: &str) -> Option<SmartHealth> {
+    let status = output.lines().find_map(|line| {
+        if line.contains("overall-health") {
+            if line.contains("PASSED") {
+                Some(SmartStatus::Passed)
+            } else if line.contains("FAILED") {
+                Some(SmartStatus::Failed)
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    })?;
+
+    // Attribute rows end with a raw value in their final column.
+    let raw_of = |needle: &str| {
+        output
+            .lines()
+            .find(|line| line.contains(needle))
+            .and_then(|line| line.split_whitespace().last())
+            .and_then(|value| value.parse::<u64>().ok())
+    };
+
+    Some(SmartHealth {
+        status,
+        reallocated_sectors: raw_of("Reallocated_Sector_Ct"),
+        wear_leveling: raw_of("Wear_Leveling_Count").or_else(|| raw_of("Media_Wearout_Indicator")),
+    })
+}
+
+/// Resolves the `/sys/class/block/<kernel-name>` directory for a block device or
+/// partition path, following a symlink (e.g. `/dev/disk/by-id/...`) the same way
+/// [`DiskExt::is_removable`] does.
+fn sys_block_path(path: &Path) -> PathBuf {
+    let kernel_name = match path.read_link() {
+        Ok(resolved) => resolved,
+        _ => path.to_path_buf(),
+    };
+
+    PathBuf::from([
+        "/sys/class/block/",
+        kernel_name
+            .file_name()
+            .expect("block device does not have a file name")
+            .to_str()
+            .unwrap(),
+    ].concat())
+}
+
+/// Reads a single unsigned integer out of a one-line sysfs attribute, returning
+/// `None` when the file is missing or does not parse.
+fn read_sys_u64(path: &Path) -> Option<u64> {
+    let mut buffer = String::new();
+    File::open(path).ok()?.read_to_string(&mut buffer).ok()?;
+    buffer.trim().parse().ok()
+}
+
+/// The I/O topology of a block device, as reported by its sysfs `queue/`
+/// attributes, translated into sectors.
+///
+/// Follows libparted's constraint model: the alignment *grain* is the optimal
+/// I/O size when the device advertises one, falling back to the minimum I/O
+/// size, and finally to a 1 MiB default. `offset` is the device's
+/// `alignment_offset`, i.e. how far the first usable sector is shifted from a
+/// grain boundary.
+struct Topology {
+    grain:  u64,
+    offset: u64,
+}
+
+impl Topology {
+    fn of(device: &Path, sector_size: u64) -> Topology {
+        let sys = sys_block_path(device);
+        let queue = sys.join("queue");
+
+        let optimal = read_sys_u64(&queue.join("optimal_io_size")).unwrap_or(0);
+        let minimum = read_sys_u64(&queue.join("minimum_io_size")).unwrap_or(0);
+
+        let bytes = if optimal != 0 {
+            optimal
+        } else if minimum != 0 {
+            minimum
+        } else {
+            1024 * 1024
+        };
+
+        let grain = match bytes / sector_size {
+            0 => 1,
+            grain => grain,
+        };
+
+        let offset = read_sys_u64(&sys.join("alignment_offset")).unwrap_or(0) / sector_size;
+
+        Topology { grain, offset: offset % grain }
+    }
+
+    /// Snaps a sector *up* to the next grain boundary, honouring the alignment
+    /// offset. Used for partition starts.
+    fn align_up(&self, sector: u64) -> u64 {
+        let Topology { grain, offset } = *self;
+        if sector <= offset {
+            return offset;
+        }
+        match (sector - offset) % grain {
+            0 => sector,
+            rem => sector + (grain - rem),
+        }
+    }
+
+    /// Snaps a sector *down* to the previous grain boundary, honouring the
+    /// alignment offset. Used for partition ends.
+    fn align_down(&self, sector: u64) -> u64 {
+        let Topology { grain, offset } = *self;
+        if sector < offset {
+            return 0;
+        }
+        ((sector - offset) / grain) * grain + offset
+    }
+}
+
+/// Reads a one-line sysfs attribute into a trimmed `String`, or `None` when the
+/// file is missing.
+fn read_sys_string(path: &Path) -> Option<String> {
+    let mut buffer = String::new();
+    File::open(path).ok()?.read_to_string(&mut buffer).ok()?;
+    Some(buffer.trim().to_owned())
+}
+
+/// Device-mapper metadata for a `/dev/mapper/<name>` or `/dev/dm-N` node,
+/// resolved through `/sys/class/block/dm-N/dm/{name,uuid}` and its `slaves/`
+/// directory.
+#[derive(Debug, Clone)]
+pub struct DeviceMap {
+    /// The device-mapper name (e.g. `cryptdata`).
+    pub name:   String,
+    /// The device-mapper UUID (e.g. `CRYPT-LUKS2-...`).
+    pub uuid:   String,
+    /// The underlying physical members backing this target.
+    pub slaves: Vec<PathBuf>,
+}
+
+/// Reads the device-mapper metadata for a block device, returning `None` when
+/// the device is not a DM node.
+fn read_device_map(device: &Path) -> Option<DeviceMap> {
+    let sys = sys_block_path(device);
+    let name = read_sys_string(&sys.join("dm/name"))?;
+    let uuid = read_sys_string(&sys.join("dm/uuid")).unwrap_or_default();
+
+    let slaves = fs::read_dir(sys.join("slaves"))
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| PathBuf::from("/dev").join(entry.file_name()))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Some(DeviceMap { name, uuid, slaves })
+}
+
+/// Builds the device path of a partition on a device, following the kernel's
+/// naming convention: names ending in a digit (`dm-0`, `nvme0n1`, `mmcblk0`)
+/// gain a `p<N>` suffix (the kpartx convention), others are suffixed directly
+/// (`sda` -> `sda1`).
+///
+/// Device-mapper nodes are presented as `/dev/mapper/<name>`, but the partitions
+/// kpartx creates on them are named after the kernel `dm-N` node, not the mapper
+/// name. The node is therefore resolved to its kernel name through sysfs first,
+/// so a mapper name that does not end in a digit (e.g. `cryptdata`) still yields
+/// `/dev/dm-Np1` rather than `cryptdata1`.
+pub(crate) fn partition_device_path(device: &Path, number: i32) -> PathBuf {
+    let kernel_name = sys_block_path(device)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(str::to_owned)
+        .unwrap_or_else(|| {
+            device
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default()
+                .to_owned()
+        });
+
+    let separator = if kernel_name.ends_with(|c: char| c.is_ascii_digit()) {
+        "p"
+    } else {
+        ""
+    };
+
+    // DM partition nodes live directly under /dev (as `dm-N`), not alongside the
+    // mapper alias in /dev/mapper; other devices keep their original directory.
+    let directory = if kernel_name.starts_with("dm-") {
+        PathBuf::from("/dev")
+    } else {
+        device
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| PathBuf::from("/dev"))
+    };
+
+    directory.join(format!("{}{}{}", kernel_name, separator, number))
+}
+
+/// Returns true if the given block device path appears as a source in the table
+/// held at `table` (either `/proc/mounts` or `/proc/swaps`), which both list the
+/// backing device in their first whitespace-delimited column. `skip_header`
+/// drops the leading row: `/proc/swaps` carries a header line, `/proc/mounts`
+/// does not, so skipping it there would silently miss the first mount entry.
+fn table_contains_device(table: &str, skip_header: bool, device: &Path) -> bool {
+    let device = match device.canonicalize() {
+        Ok(path) => path,
+        _ => device.to_path_buf(),
+    };
+
+    File::open(table)
+        .map(BufReader::new)
+        .map(|reader| {
+            reader
+                .lines()
+                .skip(if skip_header { 1 } else { 0 })
+                .filter_map(|line| line.ok())
+                .any(|line| {
+                    line.split_whitespace()
+                        .next()
+                        .map_or(false, |source| Path::new(source) == device)
+                })
+        })
+        .unwrap_or(false)
+}
+
+/// Collects every block device a device-mapper target is layered on, descending
+/// recursively through nested mappers (e.g. LVM-on-LUKS) down to the physical
+/// members, so a mount or swap living on a backing disk can be matched even when
+/// the installer presents the mapper node as the disk.
+fn device_map_slaves(device: &Path) -> Vec<PathBuf> {
+    read_device_map(device).map_or_else(Vec::new, |dm| {
+        dm.slaves
+            .iter()
+            .flat_map(|slave| {
+                let mut nested = device_map_slaves(slave);
+                nested.push(slave.clone());
+                nested
+            })
+            .collect()
+    })
+}
+
+/// Returns true if the partition is currently in use by the running system: it is
+/// mounted, active as swap, or has a device-mapper/LVM/md stack built on top of it
+/// (indicated by entries in its `holders/` directory), or is itself a
+/// device-mapper target one of whose backing members is mounted or active as swap.
+fn partition_is_busy(partition: &PartitionInfo) -> bool {
+    let device = partition.get_device_path();
+
+    let has_holders = fs::read_dir(sys_block_path(device).join("holders"))
+        .map_or(false, |mut entries| entries.next().is_some());
+
+    if has_holders
+        || table_contains_device("/proc/swaps", true, device)
+        || table_contains_device("/proc/mounts", false, device)
+    {
+        return true;
+    }
+
+    // A device-mapper target stays live while any of the disks backing it is in
+    // use, so follow the slaves down to the physical members before declaring it
+    // free to repartition.
+    device_map_slaves(device).iter().any(|slave| {
+        table_contains_device("/proc/swaps", true, slave)
+            || table_contains_device("/proc/mounts", false, slave)
+    })
+}
 
 /// Contains methods that are shared between physical and logical disk devices.
 pub trait DiskExt {
@@ -56,25 +351,28 @@ pub trait DiskExt {
             })
         };
 
+        // A device-mapper disk (LUKS/LVM/multipath) is backed by one or more
+        // physical members; follow its slaves down so a mount carried by a
+        // backing disk is attributed to the mapper node presented as the disk.
+        let check_device_map = || {
+            self.get_device_map().map_or(false, |dm| {
+                self.get_parent().map_or(false, |disks| {
+                    dm.slaves.iter().any(|slave| {
+                        disks
+                            .get_physical_device(slave)
+                            .map_or(false, |d| d.contains_mount(mount))
+                    })
+                })
+            })
+        };
+
         self.get_mount_point()
-            .map_or_else(check_partitions, |m| m == Path::new(mount))
+            .map_or_else(|| check_partitions() || check_device_map(), |m| m == Path::new(mount))
     }
 
     /// Checks if the drive is a removable drive.
     fn is_removable(&self) -> bool {
-        let path = {
-            let path = self.get_device_path();
-            PathBuf::from(match path.read_link() {
-                Ok(resolved) => [
-                    "/sys/class/block/",
-                    resolved.file_name().expect("drive does not have a file name").to_str().unwrap(),
-                ].concat(),
-                _ => [
-                    "/sys/class/block/",
-                    path.file_name().expect("drive does not have a file name").to_str().unwrap(),
-                ].concat(),
-            })
-        };
+        let path = sys_block_path(self.get_device_path());
 
         File::open(path.join("removable"))
             .ok()
@@ -82,6 +380,102 @@ pub trait DiskExt {
             .map_or(false, |res| res.ok().map_or(false, |byte| byte == b'1'))
     }
 
+    /// Returns the device-mapper metadata for this disk when it is a
+    /// `/dev/mapper/*` or `/dev/dm-N` node, or `None` for a plain block device.
+    fn get_device_map(&self) -> Option<DeviceMap> {
+        read_device_map(self.get_device_path())
+    }
+
+    /// The device-mapper name of this disk, if it is a DM target.
+    fn get_device_map_name(&self) -> Option<String> {
+        self.get_device_map().map(|dm| dm.name)
+    }
+
+    /// The device-mapper UUID of this disk, if it is a DM target.
+    fn get_device_map_uuid(&self) -> Option<String> {
+        self.get_device_map().map(|dm| dm.uuid)
+    }
+
+    /// The device path a partition with the given number would take on this
+    /// disk, honouring the `dm-N` + `p<N>` / kpartx convention for DM and other
+    /// digit-terminated device names.
+    fn get_partition_path(&self, number: i32) -> PathBuf {
+        partition_device_path(self.get_device_path(), number)
+    }
+
+    /// Checks whether the drive is a rotational device (a spinning hard disk)
+    /// rather than solid-state storage.
+    fn is_rotational(&self) -> bool {
+        read_sys_u64(&sys_block_path(self.get_device_path()).join("queue/rotational"))
+            .map_or(false, |value| value == 1)
+    }
+
+    /// Checks whether the drive advertises support for discard (TRIM), so the
+    /// installer can enable `fstrim`/`discard` mount options for SSDs and skip
+    /// them for spinning disks.
+    fn supports_discard(&self) -> bool {
+        read_sys_u64(&sys_block_path(self.get_device_path()).join("queue/discard_max_bytes"))
+            .map_or(false, |value| value > 0)
+    }
+
+    /// Queries the drive's `SMART` health via `smartctl`, returning `None` when
+    /// the tool is unavailable or the device does not support `SMART`. Callers
+    /// can surface a pre-install warning when the status is
+    /// [`SmartStatus::Failed`] or the wear/reallocation counters are climbing.
+    fn smart_status(&self) -> Option<SmartHealth> {
+        let output = Command::new("smartctl")
+            .arg("-H")
+            .arg("-A")
+            .arg(self.get_device_path())
+            .output()
+            .ok()?;
+
+        parse_smart(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// Returns the partitions which are currently in use by the running system.
+    ///
+    /// A partition is considered busy when it is mounted, active as swap, or has
+    /// entries in its `holders/` directory (a device-mapper/LVM/md stack built on
+    /// top of it). Reformatting the disk while any partition is busy would clobber
+    /// a live LUKS/LVM/RAID device, so callers should refuse to do so.
+    fn get_busy_partitions(&self) -> Vec<&PartitionInfo> {
+        self.get_partitions()
+            .iter()
+            .filter(|part| partition_is_busy(part))
+            .collect()
+    }
+
+    /// Errors if the partition with the given number is currently busy, so both
+    /// the add flow and the removal flow can refuse to disturb a partition
+    /// backing a live LUKS/LVM/RAID device rather than clobbering the running
+    /// stack. Returns `Ok(())` when no partition with that number exists.
+    fn ensure_partition_not_busy(&self, partition: i32) -> Result<(), DiskError> {
+        match self.get_partitions().iter().find(|p| p.number == partition) {
+            Some(part) if partition_is_busy(part) => {
+                Err(DiskError::PartitionInUse { id: part.number })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Flags the partition with the given number for removal at commit time,
+    /// refusing first when the partition is busy so a live LUKS/LVM/RAID stack is
+    /// never torn out from under the running system.
+    fn remove_partition(&mut self, partition: i32) -> Result<(), DiskError> {
+        self.ensure_partition_not_busy(partition)?;
+
+        if let Some(part) = self
+            .get_partitions_mut()
+            .iter_mut()
+            .find(|p| p.number == partition)
+        {
+            part.flag_enable(REMOVE);
+        }
+
+        Ok(())
+    }
+
     /// Validates that the partitions are valid for the partition table
     fn validate_partition_table(&self, part_type: PartitionType) -> Result<(), DiskError>;
 
@@ -110,25 +504,49 @@ pub trait DiskExt {
             .sum()
     }
 
+    /// The alignment grain of the device, in sectors.
+    ///
+    /// Derived from the device's I/O topology (`queue/optimal_io_size`, then
+    /// `queue/minimum_io_size`, then a 1 MiB default); partition boundaries are
+    /// rounded to a multiple of this value (shifted by the device's
+    /// `alignment_offset`) so they land on optimal-I/O boundaries on 4K-native,
+    /// SSD, and RAID devices.
+    fn get_alignment(&self) -> u64 {
+        Topology::of(self.get_device_path(), self.get_sector_size()).grain
+    }
+
     #[allow(cast_lossless)]
-    /// Calculates the requested sector from a given `Sector` variant.
+    /// Calculates the requested sector from a given `Sector` variant, snapping
+    /// the result to the device's alignment grain: positions counted from the
+    /// start of the disk ([`Sector::Start`], [`Sector::Megabyte`],
+    /// [`Sector::Percent`]) are rounded up to the next boundary, and positions
+    /// counted from the end ([`Sector::End`], [`Sector::MegabyteFromEnd`]) down
+    /// to the previous one.
+    ///
+    /// [`Sector::Unit`] is returned verbatim and [`Sector::UnitFromEnd`] is
+    /// taken back from the aligned end without further snapping — they name an
+    /// exact sector the caller has already chosen, and the final boundaries are
+    /// snapped to the grain by [`DiskExt::add_partition`].
     fn get_sector(&self, sector: Sector) -> u64 {
         const MIB2: u64 = 2 * 1024 * 1024;
 
-        let end = || self.get_sectors() - (MIB2 / self.get_sector_size());
+        let topology = Topology::of(self.get_device_path(), self.get_sector_size());
+        let reserved = MIB2 / self.get_sector_size();
+
+        let end = || topology.align_down(self.get_sectors() - reserved);
         let megabyte = |size| (size * 1_000_000) / self.get_sector_size();
 
         match sector {
-            Sector::Start => MIB2 / self.get_sector_size(),
+            Sector::Start => topology.align_up(reserved),
             Sector::End => end(),
-            Sector::Megabyte(size) => megabyte(size),
-            Sector::MegabyteFromEnd(size) => end() - megabyte(size),
+            Sector::Megabyte(size) => topology.align_up(megabyte(size)),
+            Sector::MegabyteFromEnd(size) => topology.align_down(end() - megabyte(size)),
             Sector::Unit(size) => size,
             Sector::UnitFromEnd(size) => end() - size,
-            Sector::Percent(value) => {
+            Sector::Percent(value) => topology.align_up(
                 ((self.get_sectors() * self.get_sector_size()) / ::std::u16::MAX as u64)
-                    * value as u64 / self.get_sector_size()
-            }
+                    * value as u64 / self.get_sector_size(),
+            ),
         }
     }
 
@@ -138,12 +556,36 @@ pub trait DiskExt {
     /// Adds a partition to the partition scheme.
     ///
     /// An error can occur if the partition will not fit onto the disk.
-    fn add_partition(&mut self, builder: PartitionBuilder) -> Result<(), DiskError> {
+    fn add_partition(&mut self, mut builder: PartitionBuilder) -> Result<(), DiskError> {
+        // Snap the requested boundaries onto the device's alignment grain: the
+        // start up to the next optimal-I/O boundary, the end down to the
+        // previous one. A too-small request is caught by `check_partition_size`
+        // below once the aligned length is known.
+        let topology = Topology::of(self.get_device_path(), self.get_sector_size());
+        builder.start_sector = topology.align_up(builder.start_sector);
+        builder.end_sector = topology.align_down(builder.end_sector);
+
+        // A sub-grain request can collapse to `end <= start` once snapped;
+        // reject it here before the length arithmetic below underflows (panic in
+        // debug, a gigantic wrapped length in release that slips past
+        // `check_partition_size`).
+        if builder.end_sector <= builder.start_sector {
+            return Err(DiskError::PartitionOOB);
+        }
+
         info!(
             "libdistinst: checking if {}:{} overlaps",
             builder.start_sector, builder.end_sector
         );
 
+        // Refuse to repartition a physical disk whose partitions back a live
+        // LUKS/LVM/RAID device, otherwise we would clobber the running stack.
+        if !Self::LOGICAL {
+            if let Some(busy) = self.get_busy_partitions().first() {
+                return Err(DiskError::PartitionInUse { id: busy.number });
+            }
+        }
+
         // Ensure that the values aren't already contained within an existing partition.
         if !Self::LOGICAL {
             if let Some(id) = self.overlaps_region(builder.start_sector, builder.end_sector) {
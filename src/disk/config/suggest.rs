@@ -0,0 +1,207 @@
+use super::super::{DiskError, FileSystemType, PartitionBuilder, PartitionFlag, Sector};
+use super::DiskExt;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+
+/// The partitioning scheme to generate a recommended layout for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutScheme {
+    /// A UEFI layout fronted by an EFI System Partition.
+    Efi,
+    /// A legacy BIOS layout with a dedicated `/boot`.
+    Bios,
+}
+
+const MIB: u64 = 1024 * 1024;
+const GIB: u64 = 1024 * MIB;
+
+/// A single desired mount in the suggestion table, sized in bytes.
+struct Suggestion {
+    mount:   &'static str,
+    fs:      FileSystemType,
+    flag:    Option<PartitionFlag>,
+    /// The smallest size that must be satisfied before anything else grows.
+    minsize: u64,
+    /// The preferred ceiling once every `minsize` is met, or `None` for the
+    /// trailing growable mount which simply takes whatever space is left.
+    size:    Option<u64>,
+}
+
+/// Reads `MemTotal` out of `/proc/meminfo`, in bytes, so swap can be scaled to
+/// the amount of installed RAM.
+fn installed_ram() -> u64 {
+    let mut buffer = String::new();
+    if File::open("/proc/meminfo")
+        .and_then(|mut file| file.read_to_string(&mut buffer))
+        .is_err()
+    {
+        return 0;
+    }
+
+    buffer
+        .lines()
+        .find(|line| line.starts_with("MemTotal:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|value| value.parse::<u64>().ok())
+        .map_or(0, |kib| kib * 1024)
+}
+
+/// The classic suggestion table for a scheme: an ordered list of mounts, each
+/// with a `minsize`, a preferred `size`, and a filesystem/flag. The final entry
+/// is the growable mount and therefore carries no preferred ceiling.
+fn table(scheme: LayoutScheme) -> Vec<Suggestion> {
+    // Swap is scaled to RAM, but kept within sane bounds for large machines.
+    let swap = installed_ram().max(GIB).min(8 * GIB);
+
+    let mut table = Vec::new();
+
+    match scheme {
+        LayoutScheme::Efi => table.push(Suggestion {
+            mount:   "/boot/efi",
+            fs:      FileSystemType::Fat32,
+            flag:    Some(PartitionFlag::PED_PARTITION_ESP),
+            minsize: 512 * MIB,
+            size:    Some(512 * MIB),
+        }),
+        LayoutScheme::Bios => table.push(Suggestion {
+            mount:   "/boot",
+            fs:      FileSystemType::Ext4,
+            flag:    Some(PartitionFlag::PED_PARTITION_BOOT),
+            minsize: 512 * MIB,
+            size:    Some(GIB),
+        }),
+    }
+
+    if scheme == LayoutScheme::Efi {
+        table.push(Suggestion {
+            mount:   "/boot",
+            fs:      FileSystemType::Ext4,
+            flag:    None,
+            minsize: 512 * MIB,
+            size:    Some(GIB),
+        });
+    }
+
+    table.push(Suggestion {
+        mount:   "swap",
+        fs:      FileSystemType::Swap,
+        flag:    None,
+        minsize: swap,
+        size:    Some(swap),
+    });
+
+    table.push(Suggestion {
+        mount:   "/",
+        fs:      FileSystemType::Ext4,
+        flag:    None,
+        minsize: 8 * GIB,
+        size:    Some(32 * GIB),
+    });
+
+    // The trailing growable mount: takes whatever space is left over.
+    table.push(Suggestion {
+        mount:   "/home",
+        fs:      FileSystemType::Ext4,
+        flag:    None,
+        minsize: 2 * GIB,
+        size:    None,
+    });
+
+    table
+}
+
+/// Generates a full set of [`PartitionBuilder`]s for a sensible default install.
+///
+/// The allocator walks the suggestion table, first satisfying every `minsize`
+/// against the free space on the disk (returning [`DiskError::PartitionOOB`] if
+/// the disk is too small), then distributes the surplus toward the preferred
+/// `size`s, handing the trailing growable mount whatever is left.
+pub trait SuggestLayout: DiskExt {
+    fn suggest_layout(&self, scheme: LayoutScheme) -> Result<Vec<PartitionBuilder>, DiskError> {
+        let sector_size = self.get_sector_size();
+        let to_sectors = |bytes: u64| bytes / sector_size;
+
+        let table = table(scheme);
+
+        let start = self.get_sector(Sector::Start);
+        let end = self.get_sector(Sector::End);
+        let available = end - start;
+
+        // Aligning each partition start up to the device grain costs up to one
+        // grain of padding per partition that the raw `minsize` sums do not
+        // account for. Reserve that padding up front so distributing the surplus
+        // can never push the cursor past `end` and invert the trailing mount.
+        let padding = self.get_alignment().saturating_mul(table.len() as u64);
+
+        // Every `minsize` (plus the alignment padding) must fit before anything
+        // is allowed to grow.
+        let required: u64 =
+            table.iter().map(|s| to_sectors(s.minsize)).sum::<u64>() + padding;
+        if required > available {
+            return Err(DiskError::PartitionOOB);
+        }
+
+        // Distribute the surplus toward preferred sizes; the growable mount
+        // (the one without a preferred ceiling) receives the remainder.
+        let mut surplus = available - required;
+        let mut lengths = Vec::with_capacity(table.len());
+        for suggestion in &table {
+            let minsize = to_sectors(suggestion.minsize);
+            let length = match suggestion.size {
+                Some(preferred) => {
+                    let preferred = to_sectors(preferred);
+                    let extra = preferred.saturating_sub(minsize).min(surplus);
+                    surplus -= extra;
+                    minsize + extra
+                }
+                None => minsize,
+            };
+            lengths.push(length);
+        }
+
+        if let Some(index) = table.iter().position(|s| s.size.is_none()) {
+            lengths[index] += surplus;
+        }
+
+        // Place each partition sequentially at exact sectors (`Sector::Unit`,
+        // which `get_sector` does not snap; `add_partition` aligns the
+        // boundaries later) and guard against collisions with `overlaps_region`.
+        let mut builders = Vec::with_capacity(table.len());
+        let mut cursor = start;
+        let last = table.len() - 1;
+        for (index, (suggestion, length)) in table.iter().zip(lengths).enumerate() {
+            let part_start = self.get_sector(Sector::Unit(cursor));
+            let part_end = if index == last {
+                end
+            } else {
+                self.get_sector(Sector::Unit(part_start + length))
+            };
+
+            // Guard against a zero-length or inverted extent, which
+            // `overlaps_region` would not catch.
+            if part_end <= part_start {
+                return Err(DiskError::PartitionOOB);
+            }
+
+            if let Some(id) = self.overlaps_region(part_start, part_end) {
+                return Err(DiskError::SectorOverlaps { id });
+            }
+
+            let mut builder = PartitionBuilder::new(part_start, part_end, suggestion.fs);
+            if suggestion.mount.starts_with('/') {
+                builder = builder.mount(PathBuf::from(suggestion.mount));
+            }
+            if let Some(flag) = suggestion.flag {
+                builder = builder.flag(flag);
+            }
+
+            builders.push(builder);
+            cursor = part_end;
+        }
+
+        Ok(builders)
+    }
+}
+
+impl<T: DiskExt> SuggestLayout for T {}